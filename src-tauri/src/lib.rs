@@ -1,12 +1,19 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
 use log::{info, warn};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::fs;
 use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use tauri::{
     menu::{Menu, MenuItem},
     tray::{MouseButton, MouseButtonState, TrayIconBuilder, TrayIconEvent},
     webview::PageLoadEvent,
     AppHandle, Manager, RunEvent, WebviewUrl, WebviewWindowBuilder, WindowEvent,
 };
+use tokio::sync::oneshot;
+use uuid::Uuid;
 
 /// Marker file name for permission reset
 const RESET_MARKER_FILE: &str = ".reset_permissions";
@@ -14,6 +21,81 @@ const RESET_MARKER_FILE: &str = ".reset_permissions";
 /// OAuth redirect URI prefix (navigation intercepted before load)
 const OAUTH_REDIRECT_PREFIX: &str = "http://localhost/oauth/callback";
 
+/// Label prefix for OAuth sign-in webviews. Each call to
+/// `open_oauth_window` allocates a fresh `<prefix><uuid>` label, so several
+/// sign-in attempts (e.g. linking two accounts) can be in flight at once.
+/// Any window whose label carries this prefix always shows remote,
+/// untrusted content, so it is excluded from IPC by default.
+const OAUTH_WINDOW_LABEL_PREFIX: &str = "oauth-";
+
+/// Hosts allowed to reach app IPC from inside the OAuth window.
+///
+/// Loaded once from the `oauthIpcDomains` field of `tauri.conf.json` (absent
+/// or empty means the OAuth window stays fully sandboxed, which is the
+/// expected default). Managed as app state so the invoke guard can consult
+/// it on every call without re-parsing the config.
+struct OAuthIpcAllowlist {
+    domains: Vec<String>,
+}
+
+impl OAuthIpcAllowlist {
+    fn from_config() -> Self {
+        const CONFIG: &str = include_str!("../tauri.conf.json");
+        let json: serde_json::Value =
+            serde_json::from_str(CONFIG).expect("tauri.conf.json is invalid JSON");
+        let domains = json["oauthIpcDomains"]
+            .as_array()
+            .map(|values| {
+                values
+                    .iter()
+                    .filter_map(|v| v.as_str().map(str::to_string))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self { domains }
+    }
+
+    /// Whether `host` is explicitly trusted to invoke app commands while
+    /// the OAuth window is showing it.
+    fn allows(&self, host: &str) -> bool {
+        self.domains.iter().any(|domain| domain == host)
+    }
+}
+
+/// Rejects IPC invokes made from the OAuth window unless its current,
+/// live origin host is on the [`OAuthIpcAllowlist`]. This mirrors Tauri's
+/// own remote-IPC protection: a window displaying remote content must not
+/// be able to reach app commands (e.g. `reset_webview_permissions`) just
+/// because a malicious redirect or IdP page asked it to.
+fn oauth_ipc_permitted<R: tauri::Runtime>(message: &tauri::ipc::InvokeMessage<R>) -> bool {
+    let webview = message.webview();
+    if !webview.label().starts_with(OAUTH_WINDOW_LABEL_PREFIX) {
+        return true;
+    }
+
+    let allowlist = webview.app_handle().state::<OAuthIpcAllowlist>();
+    let host = webview.url().ok().and_then(|url| url.host_str().map(str::to_string));
+
+    match host {
+        Some(host) if allowlist.allows(&host) => true,
+        Some(host) => {
+            warn!(
+                "Blocked IPC command '{}' from untrusted OAuth origin '{}'",
+                message.command(),
+                host
+            );
+            false
+        }
+        None => {
+            warn!(
+                "Blocked IPC command '{}' from OAuth window with unreadable origin",
+                message.command()
+            );
+            false
+        }
+    }
+}
+
 /// App identifier from tauri.conf.json (embedded at compile time)
 fn get_app_identifier() -> String {
     const CONFIG: &str = include_str!("../tauri.conf.json");
@@ -106,16 +188,241 @@ async fn reset_webview_permissions(app: AppHandle) -> Result<(), String> {
     std::process::exit(0);
 }
 
-/// State for OAuth window result communication
+/// State for OAuth window result communication.
+///
+/// The sender is wrapped in a `Mutex<Option<_>>` so that either the
+/// `on_page_load` redirect interceptor or the `on_window_event` close
+/// handler can fire it — whichever happens first — without racing to
+/// send twice.
 struct OAuthState {
-    /// Result of the OAuth flow (URL with code, or error message)
-    result: Mutex<Option<Result<String, String>>>,
+    sender: Mutex<Option<oneshot::Sender<Result<OAuthResult, String>>>>,
+}
+
+impl OAuthState {
+    fn new(sender: oneshot::Sender<Result<OAuthResult, String>>) -> Self {
+        Self {
+            sender: Mutex::new(Some(sender)),
+        }
+    }
+
+    /// Resolves the OAuth flow with `result`, unless it has already been
+    /// resolved.
+    fn resolve(&self, result: Result<OAuthResult, String>) {
+        if let Ok(mut sender) = self.sender.lock() {
+            if let Some(sender) = sender.take() {
+                let _ = sender.send(result);
+            }
+        }
+    }
+}
+
+/// Successful OAuth callback: the authorization `code` plus the PKCE
+/// `code_verifier` stashed by [`begin_oauth`], ready for the frontend to
+/// complete the token exchange.
+#[derive(serde::Serialize)]
+struct OAuthResult {
+    code: String,
+    code_verifier: String,
+}
+
+/// Status of one in-flight OAuth window, exposed to the frontend via
+/// [`list_oauth_windows`].
+#[derive(Clone, serde::Serialize)]
+struct OAuthWindowStatus {
+    label: String,
+    url: String,
+}
+
+/// Tracks OAuth windows currently in flight, keyed by their unique label.
+/// Managed as app state so that several concurrent sign-in attempts (e.g.
+/// linking two accounts) never collide over a single shared window or
+/// result channel.
+#[derive(Default)]
+struct OAuthRegistry {
+    windows: Mutex<HashMap<String, OAuthWindowStatus>>,
+}
+
+impl OAuthRegistry {
+    fn register(&self, label: String, url: String) {
+        if let Ok(mut windows) = self.windows.lock() {
+            windows.insert(label.clone(), OAuthWindowStatus { label, url });
+        }
+    }
+
+    fn unregister(&self, label: &str) {
+        if let Ok(mut windows) = self.windows.lock() {
+            windows.remove(label);
+        }
+    }
+
+    /// A point-in-time snapshot of currently tracked OAuth windows.
+    fn snapshot(&self) -> Vec<OAuthWindowStatus> {
+        self.windows
+            .lock()
+            .map(|windows| windows.values().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Lists OAuth windows currently in flight. Reads the live
+/// [`OAuthRegistry`], so newly-opened and already-closed windows are
+/// always reflected accurately.
+#[tauri::command]
+async fn list_oauth_windows(app: AppHandle) -> Result<Vec<OAuthWindowStatus>, String> {
+    Ok(app.state::<OAuthRegistry>().snapshot())
+}
+
+/// Derives the user's Stronghold vault encryption key from their password
+/// using Argon2.
+fn derive_vault_key(password: &str) -> Vec<u8> {
+    use argon2::{password_hash::SaltString, Argon2, PasswordHasher};
+
+    // Use a fixed salt for deterministic key derivation. This is acceptable
+    // since the password is used as a key derivation input.
+    let salt = SaltString::encode_b64(b"secludia-stronghold").expect("Invalid salt");
+    let argon2 = Argon2::default();
+
+    let hash = argon2
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Failed to hash password");
+
+    hash.hash.expect("Hash output missing").as_bytes().to_vec()
+}
+
+/// How long a PKCE `state -> code_verifier` entry is kept before it's
+/// treated as abandoned. Matches the OAuth window's own timeout in
+/// [`open_oauth_window`], since a flow that hasn't completed by then never
+/// will.
+const OAUTH_PKCE_MAX_AGE: Duration = Duration::from_secs(300);
+
+/// In-memory `state -> code_verifier` map for OAuth flows in progress.
+///
+/// A PKCE pair only needs to live for the duration of one flow, never
+/// across an app restart, so — like [`OAuthState`] and [`OAuthRegistry`]
+/// above — this is plain in-flight state rather than a separately-keyed
+/// on-disk vault.
+#[derive(Default)]
+struct OAuthPkceStore {
+    entries: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl OAuthPkceStore {
+    /// Stashes `code_verifier` under `state`, first pruning any entries
+    /// older than [`OAUTH_PKCE_MAX_AGE`] so an abandoned flow (e.g.
+    /// [`begin_oauth`] was called but no OAuth window was ever opened for it,
+    /// so the normal cancel/timeout cleanup never runs) can't accumulate
+    /// forever.
+    fn insert(&self, state: String, code_verifier: String) {
+        if let Ok(mut entries) = self.entries.lock() {
+            let now = Instant::now();
+            entries.retain(|_, (_, inserted_at)| now.duration_since(*inserted_at) < OAUTH_PKCE_MAX_AGE);
+            entries.insert(state, (code_verifier, now));
+        }
+    }
+
+    /// Removes and returns the code verifier for `state`, unless it's
+    /// missing or has expired.
+    fn take(&self, state: &str) -> Option<String> {
+        let mut entries = self.entries.lock().ok()?;
+        let (code_verifier, inserted_at) = entries.remove(state)?;
+        (inserted_at.elapsed() < OAUTH_PKCE_MAX_AGE).then_some(code_verifier)
+    }
+
+    /// Drops `state` without needing its code verifier, for cleaning up a
+    /// flow that was cancelled or timed out before any callback arrived.
+    fn remove(&self, state: &str) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.remove(state);
+        }
+    }
+}
+
+/// Number of random bytes behind each generated `state` value and PKCE
+/// code verifier.
+const OAUTH_TOKEN_BYTES: usize = 32;
+
+/// Generates a cryptographically random, URL-safe token suitable for use
+/// as an OAuth `state` value or PKCE code verifier.
+fn random_url_safe_token() -> String {
+    let mut bytes = [0u8; OAUTH_TOKEN_BYTES];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Derives the PKCE `S256` code challenge for `code_verifier` (RFC 7636
+/// §4.2): base64url, no padding, of the verifier's SHA-256 digest.
+fn pkce_code_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
 }
 
-/// Open an OAuth authentication window that intercepts the redirect callback.
-/// Returns the full callback URL (with code and state) on success.
+/// Starts an OAuth flow: generates a CSRF `state` and a PKCE code
+/// verifier, stashes `state -> code_verifier` in the in-memory PKCE store,
+/// and returns the fully-formed authorization URL with `state` and
+/// `code_challenge` appended.
+///
+/// Pair with [`open_oauth_window`], which validates the returned `state`
+/// and hands back the matching verifier once the provider redirects back.
 #[tauri::command]
-async fn open_oauth_window(app: AppHandle, url: String) -> Result<String, String> {
+async fn begin_oauth(app: AppHandle, provider_url: String) -> Result<String, String> {
+    let mut parsed_url: url::Url = provider_url
+        .parse()
+        .map_err(|e| format!("Invalid URL: {}", e))?;
+
+    let state = random_url_safe_token();
+    let code_verifier = random_url_safe_token();
+    let code_challenge = pkce_code_challenge(&code_verifier);
+
+    app.state::<OAuthPkceStore>().insert(state.clone(), code_verifier);
+
+    parsed_url
+        .query_pairs_mut()
+        .append_pair("state", &state)
+        .append_pair("code_challenge", &code_challenge)
+        .append_pair("code_challenge_method", "S256");
+
+    Ok(parsed_url.to_string())
+}
+
+/// Parses `callback_url`'s query string, validates the CSRF `state`
+/// against `store`, and returns the authorization code together with its
+/// PKCE verifier. Split out from [`parse_oauth_callback`] so the parsing
+/// and validation logic is testable without a running `AppHandle`.
+fn resolve_oauth_callback(store: &OAuthPkceStore, callback_url: &str) -> Result<OAuthResult, String> {
+    let url: url::Url = callback_url
+        .parse()
+        .map_err(|e| format!("Invalid callback URL: {}", e))?;
+    let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+
+    let code = params
+        .get("code")
+        .cloned()
+        .ok_or_else(|| "OAuth callback missing 'code'".to_string())?;
+    let state = params
+        .get("state")
+        .cloned()
+        .ok_or_else(|| "OAuth callback missing 'state'".to_string())?;
+
+    let code_verifier = store
+        .take(&state)
+        .ok_or_else(|| "OAUTH_STATE_MISMATCH".to_string())?;
+
+    Ok(OAuthResult { code, code_verifier })
+}
+
+/// Parses the OAuth redirect URL's query string, validates the CSRF
+/// `state` against the entry [`begin_oauth`] stashed in the in-memory PKCE
+/// store, and returns the authorization code together with its PKCE
+/// verifier.
+fn parse_oauth_callback(app: &AppHandle, callback_url: &str) -> Result<OAuthResult, String> {
+    resolve_oauth_callback(&app.state::<OAuthPkceStore>(), callback_url)
+}
+
+/// Open an OAuth authentication window that intercepts the redirect
+/// callback. Returns the authorization code and PKCE verifier on success,
+/// once the `state` parameter has been validated against a pending
+/// [`begin_oauth`] entry.
+#[tauri::command]
+async fn open_oauth_window(app: AppHandle, url: String) -> Result<OAuthResult, String> {
     info!("Opening OAuth window for URL: {}", url);
 
     // Parse and validate URL
@@ -128,80 +435,83 @@ async fn open_oauth_window(app: AppHandle, url: String) -> Result<String, String
         _ => return Err("OAuth URL must use HTTPS".to_string()),
     }
 
-    // Create state for communication between window event and command
-    let oauth_state = std::sync::Arc::new(OAuthState {
-        result: Mutex::new(None),
-    });
-    let state_for_handler = oauth_state.clone();
+    // Allocate a fresh label per call so several OAuth sign-in attempts can
+    // run concurrently without colliding over a single window.
+    let label = format!("{OAUTH_WINDOW_LABEL_PREFIX}{}", Uuid::new_v4());
 
-    // Create the OAuth window
-    let oauth_window = WebviewWindowBuilder::new(
-        &app,
-        "oauth",
-        WebviewUrl::External(parsed_url),
-    )
-    .title("Sign in")
-    .inner_size(500.0, 700.0)
-    .center()
-    .resizable(true)
-    .on_page_load(move |window, payload| {
-        // Intercept navigation to the redirect URI
-        if let PageLoadEvent::Started = payload.event() {
-            let url = payload.url().to_string();
-            info!("OAuth window navigating to: {}", url);
-
-            if url.starts_with(OAUTH_REDIRECT_PREFIX) {
-                info!("OAuth callback intercepted");
-
-                // Store the result
-                if let Ok(mut result) = state_for_handler.result.lock() {
-                    *result = Some(Ok(url));
+    // Create the channel before the window so the sender can be moved into
+    // the redirect interceptor before any navigation has a chance to
+    // complete.
+    let (tx, rx) = oneshot::channel();
+    let oauth_state = std::sync::Arc::new(OAuthState::new(tx));
+    let state_for_handler = oauth_state.clone();
+    let app_for_handler = app.clone();
+    let provider_url = parsed_url.to_string();
+
+    // The CSRF `state` this URL carries (set by `begin_oauth`), if any.
+    // Kept so a cancelled or timed-out flow can drop its now-unreachable
+    // PKCE entry instead of leaving it to expire on its own.
+    let pkce_state = parsed_url
+        .query_pairs()
+        .find(|(key, _)| key == "state")
+        .map(|(_, value)| value.into_owned());
+
+    // Create the OAuth window. Its label marks it as remote/untrusted so
+    // the invoke guard installed in `run()` keeps it out of app IPC.
+    let oauth_window = WebviewWindowBuilder::new(&app, &label, WebviewUrl::External(parsed_url))
+        .title("Sign in")
+        .inner_size(500.0, 700.0)
+        .center()
+        .resizable(true)
+        .on_page_load(move |window, payload| {
+            // Intercept navigation to the redirect URI
+            if let PageLoadEvent::Started = payload.event() {
+                let url = payload.url().to_string();
+                info!("OAuth window navigating to: {}", url);
+
+                if url.starts_with(OAUTH_REDIRECT_PREFIX) {
+                    info!("OAuth callback intercepted");
+                    state_for_handler.resolve(parse_oauth_callback(&app_for_handler, &url));
+                    let _ = window.close();
                 }
-
-                // Close the window
-                let _ = window.close();
             }
-        }
-    })
-    .build()
-    .map_err(|e| format!("Failed to create OAuth window: {}", e))?;
+        })
+        .build()
+        .map_err(|e| format!("Failed to create OAuth window: {}", e))?;
+
+    // Only register once the window actually exists, so a failed `.build()`
+    // above never leaves a phantom entry that nothing will ever remove.
+    app.state::<OAuthRegistry>().register(label.clone(), provider_url);
 
-    // Listen for window close event
+    // Listen for window close event. This fires for both a completed flow
+    // (the page-load handler closes the window after resolving) and a
+    // plain user cancellation, so it is also where the registry entry for
+    // this label is always cleaned up.
     let state_for_close = oauth_state.clone();
+    let app_for_close = app.clone();
+    let label_for_close = label.clone();
+    let pkce_state_for_close = pkce_state.clone();
     let _close_handler = oauth_window.on_window_event(move |event| {
         if let WindowEvent::CloseRequested { .. } | WindowEvent::Destroyed = event {
-            // If window is closed without a result, set error
-            if let Ok(mut result) = state_for_close.result.lock() {
-                if result.is_none() {
-                    *result = Some(Err("OAUTH_CANCELLED".to_string()));
-                }
+            // If window is closed without a result, this is a cancellation.
+            state_for_close.resolve(Err("OAUTH_CANCELLED".to_string()));
+            app_for_close.state::<OAuthRegistry>().unregister(&label_for_close);
+            if let Some(pkce_state) = &pkce_state_for_close {
+                app_for_close.state::<OAuthPkceStore>().remove(pkce_state);
             }
         }
     });
 
-    // Wait for result with timeout
-    let timeout = std::time::Duration::from_secs(300); // 5 minute timeout
-    let start = std::time::Instant::now();
-
-    loop {
-        // Check if we have a result
-        if let Ok(result) = oauth_state.result.lock() {
-            if let Some(ref r) = *result {
-                return r.clone();
-            }
-        }
-
-        // Check timeout
-        if start.elapsed() > timeout {
-            // Close window if still open
-            if let Some(w) = app.get_webview_window("oauth") {
+    match tokio::time::timeout(Duration::from_secs(300), rx).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(_)) => Err("OAUTH_CANCELLED".to_string()),
+        Err(_) => {
+            // Timed out: close the window if it's still open.
+            if let Some(w) = app.get_webview_window(&label) {
                 let _ = w.close();
             }
-            return Err("OAUTH_TIMEOUT".to_string());
+            Err("OAUTH_TIMEOUT".to_string())
         }
-
-        // Small delay to avoid busy waiting
-        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
     }
 }
 
@@ -219,29 +529,22 @@ pub fn run() {
         .plugin(tauri_plugin_os::init())
         .plugin(tauri_plugin_notification::init())
         .plugin(tauri_plugin_opener::init())
-        .plugin(tauri_plugin_stronghold::Builder::new(|password| {
-            // Derive key from password using argon2
-            use argon2::{Argon2, password_hash::SaltString, PasswordHasher};
-
-            // Use a fixed salt for deterministic key derivation
-            // This is acceptable since the password is used as a key derivation input
-            let salt = SaltString::encode_b64(b"secludia-stronghold").expect("Invalid salt");
-            let argon2 = Argon2::default();
-
-            let hash = argon2
-                .hash_password(password.as_bytes(), &salt)
-                .expect("Failed to hash password");
-
-            // Extract the hash output (32 bytes)
-            hash.hash
-                .expect("Hash output missing")
-                .as_bytes()
-                .to_vec()
-        }).build())
-        .invoke_handler(tauri::generate_handler![
-            reset_webview_permissions,
-            open_oauth_window
-        ])
+        .plugin(tauri_plugin_stronghold::Builder::new(derive_vault_key).build())
+        .manage(OAuthIpcAllowlist::from_config())
+        .manage(OAuthRegistry::default())
+        .manage(OAuthPkceStore::default())
+        .invoke_handler(move |invoke| {
+            if !oauth_ipc_permitted(&invoke.message) {
+                invoke.resolver.reject("IPC not permitted from this window");
+                return true;
+            }
+            tauri::generate_handler![
+                reset_webview_permissions,
+                open_oauth_window,
+                begin_oauth,
+                list_oauth_windows
+            ](invoke)
+        })
         .setup(|app| {
             // Create tray menu with just "Quit"
             let quit = MenuItem::with_id(app, "quit", "Quit", true, None::<&str>)?;
@@ -302,3 +605,119 @@ pub fn run() {
         _ => {}
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allowlist_allows_exact_host_match_only() {
+        let allowlist = OAuthIpcAllowlist {
+            domains: vec!["idp.example.com".to_string()],
+        };
+        assert!(allowlist.allows("idp.example.com"));
+        assert!(!allowlist.allows("evil.example.com"));
+        assert!(!allowlist.allows("sub.idp.example.com"));
+    }
+
+    #[test]
+    fn empty_allowlist_allows_nothing() {
+        let allowlist = OAuthIpcAllowlist { domains: vec![] };
+        assert!(!allowlist.allows("idp.example.com"));
+    }
+
+    #[test]
+    fn pkce_code_challenge_matches_rfc7636_test_vector() {
+        // RFC 7636 appendix B.
+        let verifier = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+        assert_eq!(
+            pkce_code_challenge(verifier),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn random_url_safe_token_is_unique_and_url_safe() {
+        let a = random_url_safe_token();
+        let b = random_url_safe_token();
+        assert_ne!(a, b);
+        assert!(a
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+    }
+
+    #[test]
+    fn resolve_oauth_callback_succeeds_with_matching_state() {
+        let store = OAuthPkceStore::default();
+        store.insert("abc".to_string(), "verifier".to_string());
+
+        let result =
+            resolve_oauth_callback(&store, "http://localhost/oauth/callback?code=xyz&state=abc")
+                .unwrap();
+
+        assert_eq!(result.code, "xyz");
+        assert_eq!(result.code_verifier, "verifier");
+    }
+
+    #[test]
+    fn resolve_oauth_callback_rejects_unknown_state() {
+        let store = OAuthPkceStore::default();
+
+        let err = resolve_oauth_callback(
+            &store,
+            "http://localhost/oauth/callback?code=xyz&state=unknown",
+        )
+        .unwrap_err();
+
+        assert_eq!(err, "OAUTH_STATE_MISMATCH");
+    }
+
+    #[test]
+    fn resolve_oauth_callback_rejects_state_reuse() {
+        let store = OAuthPkceStore::default();
+        store.insert("abc".to_string(), "verifier".to_string());
+        let callback_url = "http://localhost/oauth/callback?code=xyz&state=abc";
+
+        assert!(resolve_oauth_callback(&store, callback_url).is_ok());
+        // `take` consumes the entry, so a second callback with the same
+        // `state` (e.g. a replayed redirect) must not succeed again.
+        let err = resolve_oauth_callback(&store, callback_url).unwrap_err();
+
+        assert_eq!(err, "OAUTH_STATE_MISMATCH");
+    }
+
+    #[test]
+    fn resolve_oauth_callback_requires_code_param() {
+        let store = OAuthPkceStore::default();
+        store.insert("abc".to_string(), "verifier".to_string());
+
+        let err = resolve_oauth_callback(&store, "http://localhost/oauth/callback?state=abc")
+            .unwrap_err();
+
+        assert_eq!(err, "OAuth callback missing 'code'");
+    }
+
+    #[test]
+    fn resolve_oauth_callback_requires_state_param() {
+        let store = OAuthPkceStore::default();
+
+        let err = resolve_oauth_callback(&store, "http://localhost/oauth/callback?code=xyz")
+            .unwrap_err();
+
+        assert_eq!(err, "OAuth callback missing 'state'");
+    }
+
+    #[test]
+    fn resolve_oauth_callback_uses_last_value_for_duplicate_state_param() {
+        let store = OAuthPkceStore::default();
+        store.insert("second".to_string(), "verifier".to_string());
+
+        let result = resolve_oauth_callback(
+            &store,
+            "http://localhost/oauth/callback?code=xyz&state=first&state=second",
+        )
+        .unwrap();
+
+        assert_eq!(result.code_verifier, "verifier");
+    }
+}